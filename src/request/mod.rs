@@ -1,12 +1,14 @@
 //! Iron's HTTP Request representation and associated methods.
 
-use std::io::{self, Read};
+use std::io::{self, Read, Write, BufRead};
 use std::net::SocketAddr;
 use std::fmt::{self, Debug};
 
 use hyper::uri::RequestUri::{AbsoluteUri, AbsolutePath};
 use hyper::net::NetworkStream;
 use hyper::http::h1::HttpReader;
+use hyper::http::h1::HttpReader::ChunkedReader;
+use hyper::version::HttpVersion;
 
 use typemap::TypeMap;
 use plugin::Extensible;
@@ -16,10 +18,12 @@ pub use hyper::server::request::Request as HttpRequest;
 use hyper::buffer;
 
 pub use self::url::Url;
+pub use self::proxy::{TrustedProxies, Forwarded};
 
 use {Protocol, Plugin, Headers, Set, headers};
 
 mod url;
+mod proxy;
 
 /// The `Request` given to all `Middleware`.
 ///
@@ -44,6 +48,16 @@ pub struct Request<'a, 'b: 'a> {
     /// The request method.
     pub method: Method,
 
+    /// The HTTP version spoken by the client.
+    pub version: HttpVersion,
+
+    /// The percent-decoded, `/`-delimited segments of the request path.
+    ///
+    /// Computed once at construction so routers don't have to re-parse and
+    /// re-decode `url` on every request. Empty trailing segments are
+    /// preserved, so a request for `/foo/` yields `["foo", ""]`.
+    pub path_segments: Vec<String>,
+
     /// Extensible storage for data passed between middleware.
     pub extensions: TypeMap
 }
@@ -54,6 +68,7 @@ impl<'a, 'b> Debug for Request<'a, 'b> {
 
         try!(writeln!(f, "    url: {:?}", self.url));
         try!(writeln!(f, "    method: {:?}", self.method));
+        try!(writeln!(f, "    version: {:?}", self.version));
         try!(writeln!(f, "    remote_addr: {:?}", self.remote_addr));
         try!(writeln!(f, "    local_addr: {:?}", self.local_addr));
 
@@ -69,7 +84,8 @@ impl<'a, 'b> Request<'a, 'b> {
     pub fn from_http(req: HttpRequest<'a, 'b>, local_addr: SocketAddr, protocol: &Protocol)
                      -> Result<Request<'a, 'b>, String> {
         let url = try! (Request::prepare_uri(&req, local_addr, protocol));
-        let (addr, method, headers, _, _, reader) = req.deconstruct();
+        let path_segments = Request::prepare_path_segments(&req);
+        let (addr, method, headers, _, version, reader) = req.deconstruct();
 
         Ok(Request {
             url: url,
@@ -78,6 +94,8 @@ impl<'a, 'b> Request<'a, 'b> {
             headers: headers,
             body: Body::new(reader),
             method: method,
+            version: version,
+            path_segments: path_segments,
             extensions: TypeMap::new()
         })
     }
@@ -85,6 +103,7 @@ impl<'a, 'b> Request<'a, 'b> {
     /// Create a request from an incompleted HttpRequest.
     pub fn from_header(req: &HttpRequest, local_addr: SocketAddr, protocol: &Protocol) -> Result<Request<'static, 'static>, String> {
         let url = try! (Request::prepare_uri(&req, local_addr, protocol));
+        let path_segments = Request::prepare_path_segments(&req);
         Ok(Request {
             url: url,
             remote_addr: req.remote_addr,
@@ -92,6 +111,8 @@ impl<'a, 'b> Request<'a, 'b> {
             headers: req.headers.clone(),
             body: Body::empty(),
             method: req.method.clone(),
+            version: req.version,
+            path_segments: path_segments,
             extensions: TypeMap::new()
         })
     }
@@ -115,27 +136,311 @@ impl<'a, 'b> Request<'a, 'b> {
             _ => Err("Unsupported request URI".into())
         }
     }
+
+    /// Drain the request body into a `Vec<u8>`, enforcing a maximum size.
+    ///
+    /// The limit guards against unbounded or dishonest `Content-Length` values:
+    /// an oversized `Content-Length` is rejected up front, and the running total
+    /// is checked as the body streams in, so a chunked body can't exceed the
+    /// limit either.
+    pub fn body_bytes(&mut self, limit: usize) -> Result<Vec<u8>, BodyError> {
+        let content_length = self.headers.get::<headers::ContentLength>().map(|len| len.0);
+        drain_to_limit(&mut self.body, content_length, limit)
+    }
+
+    /// Deserialize an `application/json` request body into `T`.
+    ///
+    /// Errors with `BodyError::UnsupportedContentType` if the `Content-Type` is
+    /// present but is not JSON, and enforces the same `limit` as `body_bytes`.
+    #[cfg(feature = "serde")]
+    pub fn body_json<T: ::serde::de::DeserializeOwned>(&mut self, limit: usize) -> Result<T, BodyError> {
+        try!(self.expect_type("application", "json"));
+        let bytes = try!(self.body_bytes(limit));
+        ::serde_json::from_slice(&bytes).map_err(|err| BodyError::Parse(err.to_string()))
+    }
+
+    /// Deserialize an `application/x-www-form-urlencoded` request body into `T`.
+    ///
+    /// Errors with `BodyError::UnsupportedContentType` if the `Content-Type` is
+    /// present but is not form-encoded, and enforces the same `limit` as
+    /// `body_bytes`.
+    #[cfg(feature = "serde")]
+    pub fn body_form<T: ::serde::de::DeserializeOwned>(&mut self, limit: usize) -> Result<T, BodyError> {
+        try!(self.expect_type("application", "x-www-form-urlencoded"));
+        let bytes = try!(self.body_bytes(limit));
+        ::serde_urlencoded::from_bytes(&bytes).map_err(|err| BodyError::Parse(err.to_string()))
+    }
+
+    /// Check that the body's `Content-Type` matches `top`/`sub`, ignoring any
+    /// charset parameter. The structured-syntax suffix is honoured, so a `sub`
+    /// of `json` also matches `application/hal+json` or
+    /// `application/problem+json`. A missing `Content-Type` is permitted.
+    #[cfg(feature = "serde")]
+    fn expect_type(&self, top: &str, sub: &str) -> Result<(), BodyError> {
+        match self.headers.get::<headers::ContentType>() {
+            Some(content_type) => {
+                let actual_top = format!("{}", (content_type.0).0).to_lowercase();
+                let actual_sub = format!("{}", (content_type.0).1).to_lowercase();
+                let sub_matches = actual_sub == sub || actual_sub.ends_with(&format!("+{}", sub));
+                if actual_top == top && sub_matches {
+                    Ok(())
+                } else {
+                    Err(BodyError::UnsupportedContentType)
+                }
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Resolve the real client address, scheme and host when running behind a
+    /// trusted reverse proxy.
+    ///
+    /// This is opt-in: call it from middleware with the set of trusted upstream
+    /// addresses. It parses `Forwarded` (RFC 7239) and the legacy
+    /// `X-Forwarded-For`/`-Proto`/`-Host` headers, rewrites `remote_addr` to the
+    /// first untrusted hop, and — only when the direct peer is trusted —
+    /// overrides the URL scheme and host. The parsed [`Forwarded`] view is
+    /// returned so middleware can audit the forwarding chain.
+    pub fn apply_trusted_proxies(&mut self, trusted: &TrustedProxies) -> Forwarded {
+        let forwarded = Forwarded::resolve(&self.headers, self.remote_addr.ip(), trusted);
+
+        if let Some(addr) = forwarded.remote_addr {
+            self.remote_addr = SocketAddr::new(addr, self.remote_addr.port());
+        }
+
+        if forwarded.proto.is_some() || forwarded.host.is_some() {
+            let mut generic = self.url.clone().into_generic_url();
+            if let Some(ref proto) = forwarded.proto {
+                let _ = generic.set_scheme(proto);
+            }
+            if let Some(ref host) = forwarded.host {
+                let (hostname, port) = match host.rfind(':') {
+                    Some(colon) => (&host[..colon], host[colon + 1..].parse().ok()),
+                    None => (&host[..], None)
+                };
+                let _ = generic.set_host(Some(hostname));
+                let _ = generic.set_port(port);
+            }
+            if let Ok(url) = Url::from_generic_url(generic) {
+                self.url = url;
+            }
+        }
+
+        forwarded
+    }
+
+    /// Take over the raw connection for a `Connection: Upgrade` flow.
+    ///
+    /// After a middleware has verified the `Upgrade`/`Connection` headers and
+    /// written the `101 Switching Protocols` response, it can call `upgrade` to
+    /// reclaim the underlying network stream as a combined `Read + Write`
+    /// handle and speak the upgraded protocol (WebSocket, a CONNECT tunnel, …)
+    /// directly.
+    ///
+    /// This only succeeds when the request advertised an `Upgrade` token; on
+    /// any other request the `Request` is handed back unchanged so the caller
+    /// can continue to serve it normally. Any bytes the body reader had already
+    /// buffered but not yet yielded are transparently delivered first, so the
+    /// upgraded protocol sees no data loss.
+    pub fn upgrade(self) -> Result<UpgradedStream<'a, 'b>, Request<'a, 'b>> {
+        if self.headers.get::<headers::Upgrade>().is_none() || self.body.reader.is_none() {
+            return Err(self);
+        }
+
+        let reader = self.body.into_inner().unwrap();
+        Ok(UpgradedStream(reader.into_inner()))
+    }
+
+    fn prepare_path_segments(req: &HttpRequest) -> Vec<String> {
+        let path = match &req.uri {
+            &AbsoluteUri(ref url) => url.path().to_owned(),
+            &AbsolutePath(ref path) => path.clone(),
+            _ => String::new()
+        };
+
+        // Drop any query string or fragment, then decode each `/`-delimited
+        // component. Leading slashes are stripped, empty trailing segments are
+        // preserved (so `/foo/` yields `["foo", ""]`), and the root path `/`
+        // yields an empty `Vec` rather than a phantom `[""]` segment.
+        let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+        let path = path.trim_left_matches('/');
+        if path.is_empty() {
+            return Vec::new();
+        }
+        path.split('/')
+            .map(|segment| {
+                ::url::percent_encoding::percent_decode(segment.as_bytes())
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
 }
 
 /// The body of an Iron request,
-pub struct Body<'a, 'b: 'a>(Option<HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>>);
+pub struct Body<'a, 'b: 'a> {
+    reader: Option<HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>>,
+    trailers: Option<Headers>
+}
 
 impl<'a, 'b> Body<'a, 'b> {
     /// Create a new reader for use in an Iron request from a hyper HttpReader.
     pub fn new(reader: HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>) -> Body<'a, 'b> {
-        Body(Some(reader))
+        Body { reader: Some(reader), trailers: None }
     }
 
     /// Create a new fake reader.
     pub fn empty() -> Body<'a, 'b> {
-        Body(None)
+        Body { reader: None, trailers: None }
+    }
+
+    /// Reclaim the wrapped `HttpReader`, if this body is backed by a stream.
+    ///
+    /// Returns `None` for the empty body produced by `Body::empty`.
+    pub fn into_inner(self) -> Option<HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>> {
+        self.reader
+    }
+
+    /// The trailing headers sent after a chunked request body.
+    ///
+    /// HTTP/1.1 chunked transfer encoding permits a block of headers after the
+    /// final chunk. They only become available once the body has been read to
+    /// EOF; until then — and for non-chunked bodies, or a chunked body that
+    /// sent no trailers — this returns `None`.
+    pub fn trailers(&self) -> Option<&Headers> {
+        match self.trailers {
+            Some(ref headers) if headers.len() > 0 => Some(headers),
+            _ => None
+        }
+    }
+}
+
+/// A connection reclaimed from a `Request` via `Request::upgrade`.
+///
+/// Reads drain any bytes the body reader had already buffered before falling
+/// through to the live socket, and writes go straight to the socket, so the
+/// handle behaves as the raw bidirectional stream the upgraded protocol needs.
+pub struct UpgradedStream<'a, 'b: 'a>(&'a mut buffer::BufReader<&'b mut NetworkStream>);
+
+impl<'a, 'b> Read for UpgradedStream<'a, 'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, 'b> Write for UpgradedStream<'a, 'b> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
     }
 }
 
 impl<'a, 'b> Read for Body<'a, 'b> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.as_mut().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "This request don't have body yet"))
-            .and_then(|ref mut r| r.read(buf))
+        let read = try!(self.reader.as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "This request don't have body yet"))
+            .and_then(|ref mut r| r.read(buf)));
+
+        // Once a chunked body signals end-of-stream, the bytes that follow in
+        // the `BufReader` are the trailer field lines; parse them once so they
+        // can be surfaced through `trailers`.
+        if read == 0 && self.trailers.is_none() {
+            if let Some(&mut ChunkedReader(ref mut reader, _)) = self.reader.as_mut() {
+                self.trailers = Some(parse_trailers(&mut **reader));
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+/// Parse trailer field lines from `reader` into a `Headers` block, stopping at
+/// the first blank line or EOF.
+fn parse_trailers<R: BufRead>(reader: &mut R) -> Headers {
+    let mut trailers = Headers::new();
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(_) => break
+        }
+
+        let field = line.trim_right();
+        if field.is_empty() { break; }
+
+        if let Some(colon) = field.find(':') {
+            let name = field[..colon].trim().to_owned();
+            let value = field[colon + 1..].trim().to_owned();
+            trailers.set_raw(name, vec![value.into_bytes()]);
+        }
+    }
+
+    trailers
+}
+
+/// Drain `reader` into a `Vec<u8>`, enforcing `limit` both up front against a
+/// declared `content_length` and against the running total as bytes stream in.
+fn drain_to_limit<R: Read>(reader: &mut R, content_length: Option<u64>, limit: usize) -> Result<Vec<u8>, BodyError> {
+    if let Some(len) = content_length {
+        if len as usize > limit {
+            return Err(BodyError::TooLarge);
+        }
+    }
+
+    let mut body = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let read = try!(reader.read(&mut chunk).map_err(BodyError::Io));
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > limit {
+            return Err(BodyError::TooLarge);
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok(body)
+}
+
+/// An error produced while buffering or deserializing a request body.
+#[derive(Debug)]
+pub enum BodyError {
+    /// The body exceeded the caller-supplied size limit.
+    TooLarge,
+    /// The `Content-Type` did not match the requested typed parser.
+    UnsupportedContentType,
+    /// Reading from the underlying stream failed.
+    Io(io::Error),
+    /// The body could not be deserialized into the target type.
+    Parse(String)
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BodyError::TooLarge => f.write_str("request body exceeded the configured size limit"),
+            BodyError::UnsupportedContentType => f.write_str("unsupported Content-Type for request body"),
+            BodyError::Io(ref err) => write!(f, "error reading request body: {}", err),
+            BodyError::Parse(ref msg) => write!(f, "error parsing request body: {}", msg)
+        }
+    }
+}
+
+impl ::std::error::Error for BodyError {
+    fn description(&self) -> &str {
+        match *self {
+            BodyError::TooLarge => "request body too large",
+            BodyError::UnsupportedContentType => "unsupported Content-Type",
+            BodyError::Io(_) => "error reading request body",
+            BodyError::Parse(_) => "error parsing request body"
+        }
     }
 }
 
@@ -152,3 +457,47 @@ impl<'a, 'b> Extensible for Request<'a, 'b> {
 
 impl<'a, 'b> Plugin for Request<'a, 'b> {}
 impl<'a, 'b> Set for Request<'a, 'b> {}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_trailers, drain_to_limit, BodyError};
+
+    #[test]
+    fn parses_trailer_fields() {
+        let raw = b"Checksum: abc123\r\nX-Signature: deadbeef\r\n\r\n";
+        let trailers = parse_trailers(&mut &raw[..]);
+        assert_eq!(trailers.get_raw("Checksum"), Some(&[b"abc123".to_vec()][..]));
+        assert_eq!(trailers.get_raw("X-Signature"), Some(&[b"deadbeef".to_vec()][..]));
+    }
+
+    #[test]
+    fn parses_empty_trailer_block() {
+        let trailers = parse_trailers(&mut &b"\r\n"[..]);
+        assert_eq!(trailers.len(), 0);
+
+        let trailers = parse_trailers(&mut &b""[..]);
+        assert_eq!(trailers.len(), 0);
+    }
+
+    #[test]
+    fn drains_body_within_limit() {
+        let body = drain_to_limit(&mut &b"hello"[..], Some(5), 8).unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn rejects_oversized_content_length_up_front() {
+        match drain_to_limit(&mut &b"hello"[..], Some(100), 8) {
+            Err(BodyError::TooLarge) => {}
+            other => panic!("expected TooLarge, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rejects_streaming_overflow_without_content_length() {
+        match drain_to_limit(&mut &b"hello world"[..], None, 4) {
+            Err(BodyError::TooLarge) => {}
+            other => panic!("expected TooLarge, got {:?}", other)
+        }
+    }
+}
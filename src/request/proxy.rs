@@ -0,0 +1,272 @@
+//! Trusted reverse-proxy resolution for `Request`.
+//!
+//! When Iron runs behind nginx/HAProxy the immediate TCP peer is the proxy,
+//! not the client, and the wire scheme/host no longer reflect what the client
+//! actually spoke. This module parses the `Forwarded` (RFC 7239) and legacy
+//! `X-Forwarded-*` headers, but only trusts them for hops that the operator
+//! has explicitly marked as trusted upstreams, so a malicious client cannot
+//! spoof its own address.
+
+use std::net::IpAddr;
+
+use Headers;
+
+/// The set of upstream networks that are trusted to set forwarding headers.
+///
+/// Resolution only rewinds the `X-Forwarded-For` chain across hops that fall
+/// within a trusted network, and only honours `X-Forwarded-Proto`/
+/// `X-Forwarded-Host` when the direct TCP peer is itself trusted. Trusted
+/// entries are CIDR ranges, so an operator can trust a whole proxy subnet
+/// (`10.0.0.0/8`) as well as an individual address.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    trusted: Vec<(IpAddr, u8)>
+}
+
+impl TrustedProxies {
+    /// Create an empty set; with no trusted upstreams, resolution is a no-op.
+    pub fn new() -> TrustedProxies {
+        TrustedProxies { trusted: Vec::new() }
+    }
+
+    /// Mark a single upstream address as a trusted proxy (a host route).
+    pub fn trust(mut self, addr: IpAddr) -> TrustedProxies {
+        let prefix = match addr {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128
+        };
+        self.trusted.push((addr, prefix));
+        self
+    }
+
+    /// Mark a whole upstream network as trusted, e.g. `10.0.0.0/8`.
+    ///
+    /// `prefix` is the number of leading bits to match; bits beyond the address
+    /// family's width are clamped to its full width.
+    pub fn trust_cidr(mut self, network: IpAddr, prefix: u8) -> TrustedProxies {
+        self.trusted.push((network, prefix));
+        self
+    }
+
+    /// Whether `addr` falls within any trusted network.
+    pub fn is_trusted(&self, addr: &IpAddr) -> bool {
+        self.trusted.iter().any(|&(network, prefix)| in_network(*addr, network, prefix))
+    }
+}
+
+/// Whether `addr` falls within the `network`/`prefix` CIDR range. Addresses of
+/// different families never match.
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => prefix_match(&addr.octets(), &network.octets(), prefix),
+        (IpAddr::V6(addr), IpAddr::V6(network)) => prefix_match(&addr.octets(), &network.octets(), prefix),
+        _ => false
+    }
+}
+
+/// Compare the first `prefix` bits of two equal-length octet strings.
+fn prefix_match(addr: &[u8], network: &[u8], prefix: u8) -> bool {
+    let mut remaining = prefix as usize;
+    for (a, n) in addr.iter().zip(network.iter()) {
+        if remaining == 0 {
+            break;
+        } else if remaining >= 8 {
+            if a != n {
+                return false;
+            }
+            remaining -= 8;
+        } else {
+            let mask = 0xffu8 << (8 - remaining);
+            if (a & mask) != (n & mask) {
+                return false;
+            }
+            remaining = 0;
+        }
+    }
+    true
+}
+
+/// The client-facing view of a request reconstructed from forwarding headers.
+#[derive(Clone, Debug)]
+pub struct Forwarded {
+    /// The `for` chain, left-to-right, in the order received from the client
+    /// through each proxy. Exposed so middleware can audit the path. Entries
+    /// that do not parse as an IP address (such as `unknown`, an obfuscated
+    /// `_hidden` identifier, or an `X-Forwarded-For` entry carrying a port)
+    /// are left out.
+    pub for_chain: Vec<IpAddr>,
+
+    /// The real client address, found by walking `for_chain` right-to-left and
+    /// skipping every hop that is a trusted proxy. `None` when the peer is not
+    /// trusted or the chain is empty.
+    pub remote_addr: Option<IpAddr>,
+
+    /// The scheme advertised by `X-Forwarded-Proto`, honoured only when the
+    /// direct peer is trusted.
+    pub proto: Option<String>,
+
+    /// The host advertised by `X-Forwarded-Host`, honoured only when the
+    /// direct peer is trusted.
+    pub host: Option<String>
+}
+
+impl Forwarded {
+    /// Parse the forwarding headers and resolve the client view relative to the
+    /// direct TCP `peer` and the configured `trusted` upstreams.
+    pub fn resolve(headers: &Headers, peer: IpAddr, trusted: &TrustedProxies) -> Forwarded {
+        let for_chain = parse_for_chain(headers);
+
+        // Walk the chain right-to-left: as long as the current nearest hop is a
+        // trusted proxy, step past it to the hop it forwarded for. The first
+        // untrusted hop encountered is the real client.
+        let mut remote_addr = None;
+        if trusted.is_trusted(&peer) {
+            let mut index = for_chain.len();
+            while index > 0 {
+                index -= 1;
+                let hop = for_chain[index];
+                remote_addr = Some(hop);
+                if !trusted.is_trusted(&hop) {
+                    break;
+                }
+            }
+        }
+
+        // Proto and host are only believable from a trusted peer.
+        let (proto, host) = if trusted.is_trusted(&peer) {
+            (parse_raw(headers, "X-Forwarded-Proto"), parse_raw(headers, "X-Forwarded-Host"))
+        } else {
+            (None, None)
+        };
+
+        Forwarded { for_chain: for_chain, remote_addr: remote_addr, proto: proto, host: host }
+    }
+}
+
+/// Parse the `for` chain, preferring RFC 7239 `Forwarded` and falling back to
+/// the legacy `X-Forwarded-For`.
+fn parse_for_chain(headers: &Headers) -> Vec<IpAddr> {
+    if let Some(forwarded) = raw_values(headers, "Forwarded") {
+        let chain: Vec<IpAddr> = forwarded.iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(forwarded_for_node)
+            .collect();
+        if !chain.is_empty() {
+            return chain;
+        }
+    }
+
+    match raw_values(headers, "X-Forwarded-For") {
+        Some(values) => values.iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|entry| entry.trim().parse().ok())
+            .collect(),
+        None => Vec::new()
+    }
+}
+
+/// Extract the `for=` token from a single RFC 7239 forwarded-element.
+fn forwarded_for_node(element: &str) -> Option<IpAddr> {
+    element.split(';')
+        .map(|pair| pair.trim())
+        .find(|pair| pair.to_lowercase().starts_with("for="))
+        .and_then(|pair| {
+            let value = pair[4..].trim().trim_matches('"');
+            let host = if value.starts_with('[') {
+                // Bracketed IPv6: `[v6]` or `[v6]:port`. Take exactly what's
+                // between the brackets so a portless literal isn't truncated.
+                match value.find(']') {
+                    Some(end) => &value[1..end],
+                    None => return None
+                }
+            } else if value.matches(':').count() == 1 {
+                // Exactly one colon: an IPv4 `addr:port`; drop the port.
+                value.split(':').next().unwrap_or(value)
+            } else {
+                // Bare IPv4 or un-bracketed IPv6 literal: leave it intact.
+                value
+            };
+            host.parse().ok()
+        })
+}
+
+/// Read a single-valued raw header as a trimmed `String`.
+fn parse_raw(headers: &Headers, name: &str) -> Option<String> {
+    raw_values(headers, name)
+        .and_then(|values| values.into_iter().next())
+        .map(|value| value.trim().to_owned())
+}
+
+/// Read every line of a raw header as UTF-8 strings.
+fn raw_values(headers: &Headers, name: &str) -> Option<Vec<String>> {
+    headers.get_raw(name).map(|lines| {
+        lines.iter()
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use Headers;
+    use super::{TrustedProxies, Forwarded, forwarded_for_node};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_forwarded_for_nodes() {
+        assert_eq!(forwarded_for_node("for=192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(forwarded_for_node("for=192.0.2.60:443"), Some(ip("192.0.2.60")));
+        assert_eq!(forwarded_for_node("for=\"[2001:db8::1]\""), Some(ip("2001:db8::1")));
+        assert_eq!(forwarded_for_node("for=\"[2001:db8::1]:443\""), Some(ip("2001:db8::1")));
+        assert_eq!(forwarded_for_node("for=2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(forwarded_for_node("for=_hidden"), None);
+    }
+
+    #[test]
+    fn cidr_and_host_trust() {
+        let trusted = TrustedProxies::new()
+            .trust_cidr(ip("10.0.0.0"), 8)
+            .trust(ip("2001:db8::1"));
+
+        assert!(trusted.is_trusted(&ip("10.1.2.3")));
+        assert!(!trusted.is_trusted(&ip("11.0.0.1")));
+        assert!(trusted.is_trusted(&ip("2001:db8::1")));
+        assert!(!trusted.is_trusted(&ip("2001:db8::2")));
+        // Different families never match.
+        assert!(!trusted.is_trusted(&ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn resolves_real_client_behind_trusted_peer() {
+        let trusted = TrustedProxies::new().trust_cidr(ip("10.0.0.0"), 8);
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", vec![b"203.0.113.5, 10.0.0.9".to_vec()]);
+        headers.set_raw("X-Forwarded-Proto", vec![b"https".to_vec()]);
+        headers.set_raw("X-Forwarded-Host", vec![b"example.com".to_vec()]);
+
+        let forwarded = Forwarded::resolve(&headers, ip("10.0.0.1"), &trusted);
+        assert_eq!(forwarded.remote_addr, Some(ip("203.0.113.5")));
+        assert_eq!(forwarded.proto.as_ref().map(|s| &s[..]), Some("https"));
+        assert_eq!(forwarded.host.as_ref().map(|s| &s[..]), Some("example.com"));
+    }
+
+    #[test]
+    fn untrusted_peer_is_not_honoured() {
+        let trusted = TrustedProxies::new().trust_cidr(ip("10.0.0.0"), 8);
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", vec![b"203.0.113.5".to_vec()]);
+        headers.set_raw("X-Forwarded-Proto", vec![b"https".to_vec()]);
+
+        let forwarded = Forwarded::resolve(&headers, ip("198.51.100.7"), &trusted);
+        assert_eq!(forwarded.remote_addr, None);
+        assert_eq!(forwarded.proto, None);
+        assert_eq!(forwarded.host, None);
+    }
+}